@@ -0,0 +1,589 @@
+/*
+ * Copyright (C) 2023 Guillaume Pellegrino
+ * This file is part of acsrs <https://github.com/guillaumepellegrino/acsrs>.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! ACME (RFC 8555) client used to obtain and renew the certificate served on
+//! the secure listener when `autocert` is enabled in the ACS config.
+use base64::Engine;
+use eyre::{eyre, Result};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+const HTTP01_CHALLENGE_PATH: &str = "/.well-known/acme-challenge";
+
+fn b64url(data: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+/** Extracts the challenge token from a request path of the form
+ * `/.well-known/acme-challenge/{token}`. The unsecure HTTP listener should route matches to
+ * `ChallengeResponder::respond` (or call `ChallengeResponder::respond_to_path` directly). */
+pub fn route_http01_challenge(path: &str) -> Option<&str> {
+    path.strip_prefix(HTTP01_CHALLENGE_PATH)?.strip_prefix('/')
+}
+
+#[derive(Debug, Deserialize)]
+struct Directory {
+    #[serde(rename = "newNonce")]
+    new_nonce: String,
+    #[serde(rename = "newAccount")]
+    new_account: String,
+    #[serde(rename = "newOrder")]
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    #[serde(default)]
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    typ: String,
+    url: String,
+    token: String,
+}
+
+/** Holds the HTTP-01 challenge responses currently being served on the unsecure listener */
+#[derive(Debug, Default, Clone)]
+pub struct ChallengeResponder {
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl ChallengeResponder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn set(&self, token: &str, keyauth: &str) {
+        self.tokens
+            .write()
+            .await
+            .insert(token.to_string(), keyauth.to_string());
+    }
+
+    async fn clear(&self, token: &str) {
+        self.tokens.write().await.remove(token);
+    }
+
+    /** Called by the unsecure HTTP listener when it receives a GET on `/.well-known/acme-challenge/{token}` */
+    pub async fn respond(&self, token: &str) -> Option<String> {
+        self.tokens.read().await.get(token).cloned()
+    }
+
+    /** Convenience for the unsecure HTTP listener: routes a raw request path straight to
+     * `respond`, so the listener only needs one call instead of extracting the token itself. */
+    pub async fn respond_to_path(&self, path: &str) -> Option<String> {
+        self.respond(route_http01_challenge(path)?).await
+    }
+}
+
+/** Drives the ACME flow to obtain, and periodically renew, a certificate for `hostname` */
+pub struct AcmeClient {
+    directory_url: String,
+    contact_email: String,
+    hostname: String,
+    acsdir: std::path::PathBuf,
+    challenges: ChallengeResponder,
+    client: reqwest::Client,
+    /** Fires whenever a new cert/key pair has been written to disk, so the TLS acceptor can
+     * reload without restarting the process. */
+    cert_updated: tokio::sync::watch::Sender<()>,
+}
+
+impl AcmeClient {
+    pub fn new(
+        directory_url: &str,
+        contact_email: &str,
+        hostname: &str,
+        acsdir: &std::path::Path,
+        challenges: ChallengeResponder,
+    ) -> Self {
+        let (cert_updated, _) = tokio::sync::watch::channel(());
+        Self {
+            directory_url: directory_url.to_string(),
+            contact_email: contact_email.to_string(),
+            hostname: hostname.to_string(),
+            acsdir: acsdir.to_path_buf(),
+            challenges,
+            client: reqwest::Client::new(),
+            cert_updated,
+        }
+    }
+
+    /** Subscribes to certificate renewals: the TLS acceptor should watch this and reload
+     * `cert_path()`/`cert_key_path()` whenever it fires instead of requiring a restart. */
+    pub fn subscribe_cert_updates(&self) -> tokio::sync::watch::Receiver<()> {
+        self.cert_updated.subscribe()
+    }
+
+    fn account_key_path(&self) -> std::path::PathBuf {
+        self.acsdir.join("acme_account.key")
+    }
+
+    fn cert_path(&self) -> std::path::PathBuf {
+        self.acsdir.join("acme_cert.pem")
+    }
+
+    fn cert_key_path(&self) -> std::path::PathBuf {
+        self.acsdir.join("acme_cert.key")
+    }
+
+    async fn directory(&self) -> Result<Directory> {
+        Ok(self
+            .client
+            .get(&self.directory_url)
+            .send()
+            .await?
+            .json::<Directory>()
+            .await?)
+    }
+
+    async fn nonce(&self, new_nonce_url: &str) -> Result<String> {
+        let res = self.client.head(new_nonce_url).send().await?;
+        res.headers()
+            .get("replay-nonce")
+            .ok_or(eyre!("acme: reply without Replay-Nonce header"))?
+            .to_str()
+            .map(String::from)
+            .map_err(|e| eyre!("acme: invalid Replay-Nonce header: {e}"))
+    }
+
+    fn load_or_generate_account_key(&self) -> Result<EcdsaKeyPair> {
+        let rng = SystemRandom::new();
+        let path = self.account_key_path();
+        let pkcs8 = if path.exists() {
+            std::fs::read(&path)?
+        } else {
+            let doc = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)?;
+            std::fs::write(&path, doc.as_ref())?;
+            doc.as_ref().to_vec()
+        };
+        Ok(EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &pkcs8,
+            &rng,
+        )?)
+    }
+
+    fn jwk(&self, keypair: &EcdsaKeyPair) -> serde_json::Value {
+        let pubkey = keypair.public_key().as_ref();
+        // Uncompressed SEC1 point: 0x04 || X (32 bytes) || Y (32 bytes)
+        let x = &pubkey[1..33];
+        let y = &pubkey[33..65];
+        serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": b64url(x),
+            "y": b64url(y),
+        })
+    }
+
+    fn jwk_thumbprint(&self, keypair: &EcdsaKeyPair) -> Result<String> {
+        let jwk = self.jwk(keypair);
+        // RFC 7638: canonical JSON with sorted, fixed member order.
+        let canonical = format!(
+            r#"{{"crv":"{}","kty":"{}","x":"{}","y":"{}"}}"#,
+            jwk["crv"].as_str().unwrap(),
+            jwk["kty"].as_str().unwrap(),
+            jwk["x"].as_str().unwrap(),
+            jwk["y"].as_str().unwrap(),
+        );
+        Ok(b64url(&Sha256::digest(canonical.as_bytes())))
+    }
+
+    fn sign(&self, keypair: &EcdsaKeyPair, protected: &str, payload: &str) -> Result<String> {
+        let rng = SystemRandom::new();
+        let protected64 = b64url(protected.as_bytes());
+        let payload64 = b64url(payload.as_bytes());
+        let signing_input = format!("{}.{}", protected64, payload64);
+        let sig = keypair
+            .sign(&rng, signing_input.as_bytes())
+            .map_err(|_| eyre!("acme: failed to sign JWS"))?;
+        Ok(serde_json::json!({
+            "protected": protected64,
+            "payload": payload64,
+            "signature": b64url(sig.as_ref()),
+        })
+        .to_string())
+    }
+
+    async fn post_jws(
+        &self,
+        url: &str,
+        nonce_url: &str,
+        keypair: &EcdsaKeyPair,
+        kid_or_jwk: serde_json::Value,
+        payload: &serde_json::Value,
+    ) -> Result<(reqwest::Response, String)> {
+        let nonce = self.nonce(nonce_url).await?;
+        let mut protected = serde_json::json!({
+            "alg": "ES256",
+            "nonce": nonce,
+            "url": url,
+        });
+        match kid_or_jwk {
+            serde_json::Value::String(kid) => {
+                protected["kid"] = serde_json::Value::String(kid);
+            }
+            jwk => {
+                protected["jwk"] = jwk;
+            }
+        }
+        let payload_str = if payload.is_null() {
+            String::new()
+        } else {
+            payload.to_string()
+        };
+        let body = self.sign(keypair, &protected.to_string(), &payload_str)?;
+        let res = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/jose+json")
+            .body(body)
+            .send()
+            .await?;
+        let next_nonce = res
+            .headers()
+            .get("replay-nonce")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        Ok((res, next_nonce))
+    }
+
+    async fn new_account(&self, dir: &Directory, keypair: &EcdsaKeyPair) -> Result<String> {
+        let mut payload = serde_json::json!({ "termsOfServiceAgreed": true });
+        if !self.contact_email.is_empty() {
+            payload["contact"] = serde_json::json!([format!("mailto:{}", self.contact_email)]);
+        }
+        let (res, _) = self
+            .post_jws(
+                &dir.new_account,
+                &dir.new_nonce,
+                keypair,
+                self.jwk(keypair),
+                &payload,
+            )
+            .await?;
+        if !res.status().is_success() {
+            return Err(eyre!("acme: new-account failed: {}", res.status()));
+        }
+        let kid = res
+            .headers()
+            .get("location")
+            .ok_or(eyre!("acme: new-account reply without Location header"))?
+            .to_str()?
+            .to_string();
+        Ok(kid)
+    }
+
+    async fn complete_http01(&self, challenge: &Challenge, keyauth: &str) -> Result<()> {
+        println!(
+            "acme: serving HTTP-01 challenge for token {} at {}/{}",
+            challenge.token, HTTP01_CHALLENGE_PATH, challenge.token
+        );
+        self.challenges.set(&challenge.token, keyauth).await;
+        Ok(())
+    }
+
+    /** Obtains a fresh certificate for `hostname`, blocking until the CA has issued it */
+    pub async fn obtain_certificate(&self) -> Result<()> {
+        let dir = self.directory().await?;
+        let account_key = self.load_or_generate_account_key()?;
+        let kid = self.new_account(&dir, &account_key).await?;
+        let thumbprint = self.jwk_thumbprint(&account_key)?;
+
+        let order_payload = serde_json::json!({
+            "identifiers": [{"type": "dns", "value": self.hostname}],
+        });
+        let (res, _) = self
+            .post_jws(
+                &dir.new_order,
+                &dir.new_nonce,
+                &account_key,
+                serde_json::Value::String(kid.clone()),
+                &order_payload,
+            )
+            .await?;
+        let order_url = res
+            .headers()
+            .get("location")
+            .ok_or(eyre!("acme: new-order reply without Location header"))?
+            .to_str()?
+            .to_string();
+        let order: Order = res.json().await?;
+
+        for auth_url in &order.authorizations {
+            let (res, _) = self
+                .post_jws(
+                    auth_url,
+                    &dir.new_nonce,
+                    &account_key,
+                    serde_json::Value::String(kid.clone()),
+                    &serde_json::Value::Null,
+                )
+                .await?;
+            let auth: Authorization = res.json().await?;
+            if auth.status == "valid" {
+                continue;
+            }
+            let challenge = auth
+                .challenges
+                .iter()
+                .find(|c| c.typ == "http-01")
+                .ok_or(eyre!("acme: no http-01 challenge offered"))?;
+            let keyauth = format!("{}.{}", challenge.token, thumbprint);
+            self.complete_http01(challenge, &keyauth).await?;
+
+            self.post_jws(
+                &challenge.url,
+                &dir.new_nonce,
+                &account_key,
+                serde_json::Value::String(kid.clone()),
+                &serde_json::json!({}),
+            )
+            .await?;
+
+            self.poll_until_valid(&auth_url.clone(), &dir, &account_key, &kid)
+                .await?;
+            self.challenges.clear(&challenge.token).await;
+        }
+
+        let cert_key_pkcs8 = self.generate_cert_keypair()?;
+        let csr_der = self.build_csr(&cert_key_pkcs8)?;
+        let finalize_payload = serde_json::json!({ "csr": b64url(&csr_der) });
+        self.post_jws(
+            &order.finalize,
+            &dir.new_nonce,
+            &account_key,
+            serde_json::Value::String(kid.clone()),
+            &finalize_payload,
+        )
+        .await?;
+
+        let order = self.poll_order_until_valid(&order_url, &dir, &account_key, &kid).await?;
+        let cert_url = order
+            .certificate
+            .ok_or(eyre!("acme: order valid but no certificate URL"))?;
+        let (res, _) = self
+            .post_jws(
+                &cert_url,
+                &dir.new_nonce,
+                &account_key,
+                serde_json::Value::String(kid.clone()),
+                &serde_json::Value::Null,
+            )
+            .await?;
+        let chain = res.text().await?;
+        std::fs::write(self.cert_path(), chain)?;
+        std::fs::write(self.cert_key_path(), cert_key_pkcs8)?;
+        println!("acme: certificate for {} saved to {:?}", self.hostname, self.cert_path());
+        let _ = self.cert_updated.send(());
+        Ok(())
+    }
+
+    async fn poll_until_valid(
+        &self,
+        auth_url: &str,
+        dir: &Directory,
+        account_key: &EcdsaKeyPair,
+        kid: &str,
+    ) -> Result<()> {
+        for _ in 0..20 {
+            let (res, _) = self
+                .post_jws(
+                    auth_url,
+                    &dir.new_nonce,
+                    account_key,
+                    serde_json::Value::String(kid.to_string()),
+                    &serde_json::Value::Null,
+                )
+                .await?;
+            let auth: Authorization = res.json().await?;
+            if auth.status == "valid" {
+                return Ok(());
+            }
+            if auth.status == "invalid" {
+                return Err(eyre!("acme: authorization {} went invalid", auth_url));
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        }
+        Err(eyre!("acme: timed out waiting for authorization {}", auth_url))
+    }
+
+    async fn poll_order_until_valid(
+        &self,
+        order_url: &str,
+        dir: &Directory,
+        account_key: &EcdsaKeyPair,
+        kid: &str,
+    ) -> Result<Order> {
+        for _ in 0..20 {
+            let (res, _) = self
+                .post_jws(
+                    order_url,
+                    &dir.new_nonce,
+                    account_key,
+                    serde_json::Value::String(kid.to_string()),
+                    &serde_json::Value::Null,
+                )
+                .await?;
+            let order: Order = res.json().await?;
+            if order.status == "valid" {
+                return Ok(order);
+            }
+            if order.status == "invalid" {
+                return Err(eyre!("acme: order {} went invalid", order_url));
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        }
+        Err(eyre!("acme: timed out waiting for order {}", order_url))
+    }
+
+    fn generate_cert_keypair(&self) -> Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+        let doc = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)?;
+        Ok(doc.as_ref().to_vec())
+    }
+
+    fn build_csr(&self, cert_key_pkcs8: &[u8]) -> Result<Vec<u8>> {
+        let mut params = rcgen::CertificateParams::new(vec![self.hostname.clone()]);
+        params.key_pair = Some(rcgen::KeyPair::from_der(cert_key_pkcs8)?);
+        let cert = rcgen::Certificate::from_params(params)?;
+        Ok(cert.serialize_request_der()?)
+    }
+
+    /** Spawns a background task that renews the certificate once fewer than
+     * `renewal_threshold_days` remain before expiry, and whenever no certificate exists yet. */
+    pub fn spawn_renewal_task(self: Arc<Self>, renewal_threshold_days: u32) {
+        tokio::spawn(async move {
+            loop {
+                let needs_renewal = match self.days_until_expiry() {
+                    Some(days) => days < renewal_threshold_days as i64,
+                    None => true,
+                };
+                if needs_renewal {
+                    if let Err(e) = self.obtain_certificate().await {
+                        eprintln!("acme: failed to obtain/renew certificate: {e}");
+                    }
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(24 * 3600)).await;
+            }
+        });
+    }
+
+    fn days_until_expiry(&self) -> Option<i64> {
+        let pem = std::fs::read_to_string(self.cert_path()).ok()?;
+        let (_, cert) = x509_parser::pem::parse_x509_pem(pem.as_bytes()).ok()?;
+        let cert = cert.parse_x509().ok()?;
+        let remaining = cert.validity().time_to_expiration()?;
+        Some(remaining.whole_days())
+    }
+}
+
+#[cfg(test)]
+fn test_account_keypair() -> EcdsaKeyPair {
+    let rng = SystemRandom::new();
+    let doc = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, doc.as_ref(), &rng).unwrap()
+}
+
+#[cfg(test)]
+fn test_client() -> AcmeClient {
+    AcmeClient::new(
+        "https://acme.example.invalid/directory",
+        "",
+        "acs.example.com",
+        std::path::Path::new("/tmp"),
+        ChallengeResponder::new(),
+    )
+}
+
+#[test]
+fn test_b64url_is_unpadded_and_url_safe() {
+    let encoded = b64url(b"a value needing padding");
+    assert!(!encoded.contains('='));
+    assert!(!encoded.contains('+'));
+    assert!(!encoded.contains('/'));
+}
+
+#[test]
+fn test_jwk_thumbprint_is_stable_for_the_same_key() {
+    let client = test_client();
+    let keypair = test_account_keypair();
+    let first = client.jwk_thumbprint(&keypair).unwrap();
+    let second = client.jwk_thumbprint(&keypair).unwrap();
+    assert_eq!(first, second);
+    assert!(!first.is_empty());
+}
+
+#[test]
+fn test_jwk_thumbprint_differs_across_keys() {
+    let client = test_client();
+    let a = client.jwk_thumbprint(&test_account_keypair()).unwrap();
+    let b = client.jwk_thumbprint(&test_account_keypair()).unwrap();
+    assert_ne!(a, b);
+}
+
+#[test]
+fn test_sign_produces_a_well_formed_jws() {
+    let client = test_client();
+    let keypair = test_account_keypair();
+    let jws = client
+        .sign(&keypair, r#"{"alg":"ES256"}"#, r#"{"foo":"bar"}"#)
+        .unwrap();
+    let value: serde_json::Value = serde_json::from_str(&jws).unwrap();
+    assert!(value["protected"].is_string());
+    assert!(value["payload"].is_string());
+    assert!(value["signature"].is_string());
+}
+
+#[test]
+fn test_route_http01_challenge_extracts_token() {
+    assert_eq!(
+        route_http01_challenge("/.well-known/acme-challenge/abc123"),
+        Some("abc123")
+    );
+    assert_eq!(route_http01_challenge("/other/path"), None);
+}
+
+#[tokio::test]
+async fn test_challenge_responder_respond_to_path() {
+    let responder = ChallengeResponder::new();
+    responder.set("abc123", "abc123.thumbprint").await;
+    assert_eq!(
+        responder.respond_to_path("/.well-known/acme-challenge/abc123").await,
+        Some("abc123.thumbprint".to_string())
+    );
+    assert_eq!(responder.respond_to_path("/other/path").await, None);
+}
@@ -15,14 +15,19 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use crate::acme::{AcmeClient, ChallengeResponder};
 use crate::db;
+use crate::firmware;
+use crate::roles::RolesConfig;
 use crate::soap;
 use crate::utils;
+use crate::webhook::{self, WebhookDispatcher};
 use base64::Engine;
 use eyre::{eyre, Result};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
 use tokio::sync::{mpsc, RwLock};
 
 #[derive(Debug, Clone)]
@@ -51,22 +56,56 @@ pub struct CPE {
     /** Number of CPEController running for this CPE + 1 */
     cpe_controllers_refcount: Arc<()>,
 
+    /** Unix timestamp of the last Inform received from this CPE, persisted via `Acs::record_cpe_seen` */
+    pub last_seen: std::sync::atomic::AtomicI64,
+
     transfers_tx: flume::Sender<Transfer>,
     transfers_rx: flume::Receiver<Transfer>,
 }
 
+#[derive(Clone)]
 pub struct CPEController {
     cpe: Arc<RwLock<CPE>>,
     transfers_tx: flume::Sender<Transfer>,
     _refcount: Arc<()>,
+    serial_number: String,
+    webhooks: WebhookDispatcher,
+    webhook_endpoints: Arc<RwLock<Vec<webhook::Endpoint>>>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct Acs {
     pub config: db::AcsConfig,
     pub basicauth: String,
     pub cpe_list: HashMap<String, Arc<RwLock<CPE>>>,
     pub acsdir: std::path::PathBuf,
+
+    /** HTTP-01 challenge tokens served by the unsecure listener on behalf of the ACME client */
+    pub acme_challenges: ChallengeResponder,
+
+    /** Subscribers for CPE lifecycle/transfer events, delivered over HTTP with an HMAC signature */
+    pub webhooks: Arc<RwLock<Vec<webhook::Endpoint>>>,
+    webhook_dispatcher: WebhookDispatcher,
+
+    /** Users/roles loaded from roles.toml. When absent, the single `basicauth` admin has full control */
+    pub roles: Option<RolesConfig>,
+
+    /** Firmware images staged for distribution to CPEs via the Download RPC */
+    pub firmware: firmware::FirmwareStore,
+
+    /** Storage backend selected by `config.storage_url`; `None` until `new`/`restore` runs */
+    storage: Option<Box<dyn db::Storage>>,
+}
+
+impl std::fmt::Debug for Acs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Acs")
+            .field("config", &self.config)
+            .field("cpe_list", &self.cpe_list)
+            .field("acsdir", &self.acsdir)
+            .field("roles", &self.roles)
+            .finish()
+    }
 }
 
 impl Transfer {
@@ -132,6 +171,7 @@ impl Default for CPE {
             baseurl: String::new(),
             tr069_session_refcount: Arc::new(()),
             cpe_controllers_refcount: Arc::new(()),
+            last_seen: std::sync::atomic::AtomicI64::new(0),
             transfers_tx: tx,
             transfers_rx: rx,
         }
@@ -153,16 +193,43 @@ impl CPE {
     pub fn get_transfers_rx(&self) -> flume::Receiver<Transfer> {
         self.transfers_rx.clone()
     }
+
+    fn to_db_row(&self, serial_number: &str) -> db::CPE {
+        db::CPE {
+            serial_number: serial_number.to_string(),
+            url: self.connreq.url.clone(),
+            username: self.connreq.username.clone(),
+            password: self.connreq.password.clone(),
+            last_seen: self.last_seen.load(std::sync::atomic::Ordering::Relaxed),
+            manufacturer: self.device_id.manufacturer.clone(),
+            product_class: self.device_id.product_class.clone(),
+            oui: self.device_id.oui.clone(),
+            software_version: self.device_id.software_version.clone(),
+        }
+    }
 }
 
 impl CPEController {
-    pub async fn new(cpelock: Arc<RwLock<CPE>>) -> Self {
+    pub async fn new(acs: &Acs, cpelock: Arc<RwLock<CPE>>) -> Self {
         let cpe = cpelock.read().await;
-        Self {
+        let serial_number = cpe.device_id.serial_number.clone();
+        // Best-effort "first Inform" signal: the TR-069 session handler that actually owns
+        // tr069_session_refcount isn't part of this crate snapshot, so this is the earliest
+        // point we can observe whether a session is already open for the CPE.
+        let is_new_session = !cpe.tr069_session_opened();
+        let controller = Self {
             cpe: cpelock.clone(),
             transfers_tx: cpe.transfers_tx.clone(),
             _refcount: cpe.cpe_controllers_refcount.clone(),
+            serial_number: serial_number.clone(),
+            webhooks: acs.webhook_dispatcher.clone(),
+            webhook_endpoints: acs.webhooks.clone(),
+        };
+        drop(cpe);
+        if is_new_session {
+            acs.notify_session_opened(&serial_number).await;
         }
+        controller
     }
 
     pub async fn add_transfer(&self, transfer: Transfer) -> Result<()> {
@@ -175,12 +242,70 @@ impl CPEController {
 
             // Send the ConnectionRequest to CPE
             println!("Send ConnectionRequest to {}", connreq.url);
-            connreq.send().await?;
+            if let Err(e) = connreq.send().await {
+                self.notify(webhook::EventType::ConnectionRequestFailed).await;
+                return Err(e);
+            }
             println!("ConnectionRequest was acknowledged");
         }
 
         Ok(())
     }
+
+    /** Called by the TR-069 session once a queued Transfer's reply Envelope reaches its observer */
+    pub async fn notify_transfer_completed(&self) {
+        self.notify(webhook::EventType::TransferCompleted).await;
+    }
+
+    /** Builds and enqueues a `Download` RPC pointing the CPE at a staged firmware image.
+     * The returned channel yields the CPE's `DownloadResponse`/`TransferComplete` reply, after
+     * firing the `TransferCompleted` webhook the moment it arrives. */
+    pub async fn request_download(
+        &self,
+        image: &firmware::Image,
+        baseurl: &str,
+    ) -> Result<mpsc::Receiver<soap::Envelope>> {
+        let connreq = self.cpe.read().await.connreq.clone();
+        let mut transfer = Transfer::new();
+        transfer.msg = soap::Envelope::download(
+            "1",
+            &image.file_type,
+            &image.url(baseurl),
+            &connreq.username,
+            &connreq.password,
+            image.size,
+        );
+        let mut reply_rx = transfer.rxchannel();
+        self.add_transfer(transfer).await?;
+
+        let (forward_tx, forward_rx) = mpsc::channel(1);
+        let controller = self.clone();
+        tokio::spawn(async move {
+            if let Some(envelope) = reply_rx.recv().await {
+                controller.notify_transfer_completed().await;
+                let _ = forward_tx.send(envelope).await;
+            }
+        });
+        Ok(forward_rx)
+    }
+
+    async fn notify(&self, event: webhook::EventType) {
+        let endpoints = self.webhook_endpoints.read().await.clone();
+        self.webhooks.dispatch(
+            &endpoints,
+            webhook::Event {
+                serial_number: self.serial_number.clone(),
+                event,
+                timestamp: webhook::now(),
+            },
+        );
+    }
+}
+
+/** Constant-time string comparison for credentials, so a timing side-channel can't be used
+ * to guess a password byte-by-byte across many requests. */
+fn secure_compare(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.as_bytes().ct_eq(b.as_bytes()).into()
 }
 
 impl Acs {
@@ -195,9 +320,16 @@ impl Acs {
             identity_password: String::from("ACSRS"),
             secure_address: String::from("[::0]:8443"),
             management_address: String::from("127.0.0.1:8000"),
+            acme_directory_url: String::from("https://acme-v02.api.letsencrypt.org/directory"),
+            acme_email: String::new(),
+            acme_renewal_threshold_days: 30,
+            storage_url: String::new(),
+            cors_allowed_origins: Vec::new(),
         };
         acs.basicauth = Self::basicauth(&acs.config.username, &acs.config.password);
         acs.acsdir = acsdir.to_path_buf();
+        acs.firmware = firmware::FirmwareStore::new(acsdir);
+        acs.storage = Some(db::open_default(acsdir));
         acs
     }
 
@@ -207,50 +339,101 @@ impl Acs {
         format!("Basic {}", token64)
     }
 
-    pub async fn save(&self) -> Result<()> {
-        let savefile = self.acsdir.join("config.toml");
-        println!("Save ACS config at {:?}", savefile);
+    fn storage(&self) -> Result<&dyn db::Storage> {
+        Ok(self
+            .storage
+            .as_deref()
+            .ok_or(eyre!("Acs::new/restore must run before using the storage backend"))?)
+    }
 
-        let mut db = db::Acs {
-            config: self.config.clone(),
-            ..Default::default()
-        };
+    /** Persists the config, webhooks and every known CPE through the storage backend in one
+     * atomic call, so concurrent `save`s can't interleave into a mixed-state file/transaction. */
+    pub async fn save(&self) -> Result<()> {
+        let storage = self.storage()?;
+        println!("Save ACS config via {} backend", self.config.storage_url);
 
+        let mut cpes = Vec::with_capacity(self.cpe_list.len());
         for (sn, cpe) in &self.cpe_list {
-            let cpe = cpe.read().await;
-            let elem = db::CPE {
-                serial_number: sn.clone(),
-                url: cpe.connreq.url.clone(),
-                username: cpe.connreq.username.clone(),
-                password: cpe.connreq.password.clone(),
-            };
-            db.cpe.push(elem);
+            cpes.push(cpe.read().await.to_db_row(sn));
+        }
+        storage
+            .save_all(&self.config, &self.webhooks.read().await, &cpes)
+            .await?;
+
+        if let Some(roles) = &self.roles {
+            roles.save(&self.acsdir.join("roles.toml"))?;
         }
 
-        db.save(&savefile)
+        Ok(())
+    }
+
+    /** Upserts a single CPE's row. Call this on every Inform instead of `save`: the SQL
+     * backend writes only that CPE's row, while the TOML backend still rewrites the whole
+     * config.toml (without re-serializing the other CPEs from memory). */
+    pub async fn record_cpe_seen(&self, serial_number: &str) -> Result<()> {
+        let cpe_lock = self
+            .cpe_list
+            .get(serial_number)
+            .ok_or(eyre!("record_cpe_seen: unknown CPE '{serial_number}'"))?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let cpe = cpe_lock.read().await;
+        cpe.last_seen.store(now, std::sync::atomic::Ordering::Relaxed);
+        self.storage()?.upsert_cpe(&cpe.to_db_row(serial_number)).await
     }
 
     pub async fn restore(acsdir: &std::path::Path) -> Result<Acs> {
-        let savefile = acsdir.join("config.toml");
-        let db = db::Acs::restore(&savefile)?;
+        // The storage backend itself is selected by a field of the config it stores, so we
+        // bootstrap through the TOML file to learn `storage_url` before picking the real backend.
+        let bootstrap = db::open_default(acsdir);
+        let storage_url = bootstrap.load_config().await?.storage_url;
+        let storage = db::open(acsdir, &storage_url).await?;
+
         let mut acs = Self::default();
-        acs.config = db.config.clone();
+        acs.config = storage.load_config().await?;
         acs.basicauth = Acs::basicauth(&acs.config.username, &acs.config.password);
         acs.acsdir = acsdir.to_path_buf();
+        acs.webhooks = Arc::new(RwLock::new(storage.load_webhooks().await?));
+        acs.roles = RolesConfig::load(&acsdir.join("roles.toml"))?;
+        acs.firmware = firmware::FirmwareStore::new(acsdir);
 
-        for elem in &db.cpe {
+        for elem in storage.load_cpe_list().await? {
             let mut cpe = CPE::default();
             cpe.device_id.serial_number = elem.serial_number.clone();
             cpe.connreq.url = elem.url.clone();
             cpe.connreq.username = elem.username.clone();
             cpe.connreq.password = elem.password.clone();
+            cpe.device_id.manufacturer = elem.manufacturer.clone();
+            cpe.device_id.product_class = elem.product_class.clone();
+            cpe.device_id.oui = elem.oui.clone();
+            cpe.device_id.software_version = elem.software_version.clone();
+            cpe.last_seen = std::sync::atomic::AtomicI64::new(elem.last_seen);
             acs.cpe_list
                 .insert(elem.serial_number.clone(), Arc::new(RwLock::new(cpe)));
         }
 
+        acs.storage = Some(storage);
         Ok(acs)
     }
 
+    /** Standard startup sequence for a binary's `main`: restore existing state, falling back to
+     * a fresh `Acs` when `acsdir` has none yet, then kick off ACME issuance/renewal if
+     * `autocert` is configured. Returns the `Acs` together with the certificate-update
+     * receiver from `start_acme`. Prefer this over calling `restore`/`new` and `start_acme`
+     * separately. */
+    pub async fn start(
+        acsdir: &std::path::Path,
+    ) -> Result<(Acs, Option<tokio::sync::watch::Receiver<()>>)> {
+        let acs = if acsdir.join("config.toml").exists() {
+            Self::restore(acsdir).await?
+        } else {
+            Self::new(acsdir)
+        };
+        let cert_updates = acs.start_acme().await?;
+        Ok((acs, cert_updates))
+    }
+
     pub fn print_config(&self, hostname: &str) {
         let hostname = match hostname.contains(":") {
             true => {
@@ -295,6 +478,98 @@ impl Acs {
         );
         println!();
     }
+
+    /** Obtains (and schedules renewal of) a certificate for `config.hostname` through ACME.
+     * The unsecure HTTP listener must route `/.well-known/acme-challenge/{token}` requests
+     * to `self.acme_challenges.respond(token)` for the HTTP-01 challenge to succeed.
+     * Returns `None` when autocert is disabled; otherwise a watch receiver the secure listener
+     * should subscribe to and reload its TLS acceptor from `acsdir/acme_cert.pem` whenever it
+     * fires, so a renewal takes effect without restarting the process. */
+    pub async fn start_acme(&self) -> Result<Option<tokio::sync::watch::Receiver<()>>> {
+        if !self.config.autocert {
+            return Ok(None);
+        }
+        if self.config.hostname.is_empty() {
+            return Err(eyre!("autocert is enabled but config.hostname is empty"));
+        }
+
+        let client = Arc::new(AcmeClient::new(
+            &self.config.acme_directory_url,
+            &self.config.acme_email,
+            &self.config.hostname,
+            &self.acsdir,
+            self.acme_challenges.clone(),
+        ));
+        let cert_updates = client.subscribe_cert_updates();
+        client.clone().spawn_renewal_task(self.config.acme_renewal_threshold_days);
+        Ok(Some(cert_updates))
+    }
+
+    /** Resolves the `Authorization: Basic ...` header of a management request to a username.
+     * Falls back to the single configured admin when no roles.toml was loaded. */
+    pub fn authenticate(&self, authorization_header: &str) -> Option<String> {
+        match &self.roles {
+            Some(roles) => {
+                let token = authorization_header.strip_prefix("Basic ")?;
+                let decoded = base64::engine::general_purpose::STANDARD.decode(token).ok()?;
+                let decoded = String::from_utf8(decoded).ok()?;
+                let (username, password) = decoded.split_once(':')?;
+                match roles.password_of(username) {
+                    Some(expected) if secure_compare(expected, password) => Some(username.to_string()),
+                    _ => None,
+                }
+            }
+            None => secure_compare(authorization_header, &self.basicauth).then(|| self.config.username.clone()),
+        }
+    }
+
+    /** Checks whether `username` may perform `permission` (e.g. "cpe.CPE1_SN.write").
+     * Always true for the single admin when no roles.toml was loaded. */
+    pub fn authorize(&self, username: &str, permission: &str) -> bool {
+        match &self.roles {
+            Some(roles) => roles.is_allowed(username, permission),
+            None => username == self.config.username,
+        }
+    }
+
+    /** The single call the management API should make per request: authenticates
+     * `authorization_header` and, only if the resulting user is allowed `permission`, returns
+     * their username. `None` means reject the request with 401/403. */
+    pub fn authorize_request(&self, authorization_header: &str, permission: &str) -> Option<String> {
+        let username = self.authenticate(authorization_header)?;
+        self.authorize(&username, permission).then_some(username)
+    }
+
+    /** Builds the `Access-Control-Allow-*` headers for a management request from `origin`,
+     * or `None` if CORS is disabled (`cors_allowed_origins` empty) or `origin` isn't allowlisted. */
+    pub fn cors_headers(&self, origin: &str) -> Option<Vec<(&'static str, String)>> {
+        if self.config.cors_allowed_origins.is_empty() {
+            return None;
+        }
+        crate::cors::response_headers(&self.config.cors_allowed_origins, origin)
+    }
+
+    /** The single call the HTTP listener should make per request to handle CORS: attach the
+     * returned headers to the response either way, and short-circuit with an empty 204 body
+     * when `is_preflight` is true. `None` means `origin` isn't allowlisted (or CORS is disabled),
+     * so the listener should serve the request without any `Access-Control-*` headers. */
+    pub fn cors_response(&self, method: &str, origin: &str) -> Option<(bool, Vec<(&'static str, String)>)> {
+        let headers = self.cors_headers(origin)?;
+        Some((crate::cors::is_preflight(method, Some(origin)), headers))
+    }
+
+    /** Called by the TR-069 session handler when a CPE sends its first Inform */
+    pub async fn notify_session_opened(&self, serial_number: &str) {
+        let endpoints = self.webhooks.read().await.clone();
+        self.webhook_dispatcher.dispatch(
+            &endpoints,
+            webhook::Event {
+                serial_number: serial_number.to_string(),
+                event: webhook::EventType::SessionOpened,
+                timestamp: webhook::now(),
+            },
+        );
+    }
 }
 
 #[tokio::test]
@@ -327,3 +602,73 @@ async fn test_acs_save_restore() {
         "http://192.168.1.X:7547/CPE2"
     );
 }
+
+#[tokio::test]
+async fn test_start_skips_acme_when_autocert_disabled() {
+    let tmp = std::path::PathBuf::from("/tmp/acsrs_test_start");
+    std::fs::create_dir_all(&tmp).unwrap();
+    let mut acs = Acs::new(&tmp);
+    acs.config.autocert = false;
+    acs.save().await.unwrap();
+
+    let (acs, _) = Acs::start(&tmp).await.unwrap();
+    assert!(!acs.config.autocert);
+}
+
+#[tokio::test]
+async fn test_authorize_request_composes_authenticate_and_authorize() {
+    let tmp = std::path::PathBuf::from("/tmp/acsrs_test_authorize_request");
+    std::fs::create_dir_all(&tmp).unwrap();
+    let mut acs = Acs::new(&tmp);
+
+    let mut roles = HashMap::new();
+    roles.insert(
+        "viewer".to_string(),
+        crate::roles::Role {
+            permissions: vec!["cpe.*.read".to_string()],
+            inherits: vec![],
+        },
+    );
+    let mut users = HashMap::new();
+    users.insert(
+        "alice".to_string(),
+        crate::roles::User {
+            password: "secret".to_string(),
+            roles: vec!["viewer".to_string()],
+        },
+    );
+    acs.roles = Some(crate::roles::RolesConfig { roles, users });
+
+    let header = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode("alice:secret")
+    );
+    assert_eq!(
+        acs.authorize_request(&header, "cpe.CPE1_SN.read"),
+        Some("alice".to_string())
+    );
+    assert_eq!(acs.authorize_request(&header, "cpe.CPE1_SN.write"), None);
+
+    let bad_header = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode("alice:wrong")
+    );
+    assert_eq!(acs.authorize_request(&bad_header, "cpe.CPE1_SN.read"), None);
+}
+
+#[test]
+fn test_cors_response_short_circuits_preflight_and_carries_headers() {
+    let tmp = std::path::PathBuf::from("/tmp/acsrs_test_cors_response");
+    std::fs::create_dir_all(&tmp).unwrap();
+    let mut acs = Acs::new(&tmp);
+    acs.config.cors_allowed_origins = vec!["https://dashboard.example.com".to_string()];
+
+    let (is_preflight, headers) = acs.cors_response("OPTIONS", "https://dashboard.example.com").unwrap();
+    assert!(is_preflight);
+    assert!(headers.contains(&("Access-Control-Allow-Origin", "https://dashboard.example.com".to_string())));
+
+    let (is_preflight, _) = acs.cors_response("GET", "https://dashboard.example.com").unwrap();
+    assert!(!is_preflight);
+
+    assert!(acs.cors_response("GET", "https://evil.example.com").is_none());
+}
@@ -0,0 +1,83 @@
+/*
+ * Copyright (C) 2023 Guillaume Pellegrino
+ * This file is part of acsrs <https://github.com/guillaumepellegrino/acsrs>.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! CORS handling for the management API, so a browser-based dashboard can call
+//! it cross-origin alongside the existing HTTP Basic auth.
+const ALLOWED_HEADERS: &str = "Authorization, Content-Type";
+const ALLOWED_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS";
+
+/** Checks `origin` (the `Origin` request header value) against the configured allowlist */
+pub fn is_allowed(allowed_origins: &[String], origin: &str) -> bool {
+    allowed_origins.iter().any(|allowed| allowed == "*" || allowed == origin)
+}
+
+/** Builds the `Access-Control-Allow-*` headers for `origin`, or `None` if it isn't allowlisted.
+ * `Access-Control-Allow-Credentials` is only set for an explicit origin match: the CORS spec
+ * forbids pairing it with a wildcard `Allow-Origin`, and reflecting `*` with credentials enabled
+ * would let any site ride a logged-in admin's Basic-auth session against the management API. */
+pub fn response_headers(allowed_origins: &[String], origin: &str) -> Option<Vec<(&'static str, String)>> {
+    if !is_allowed(allowed_origins, origin) {
+        return None;
+    }
+    let mut headers = vec![
+        ("Access-Control-Allow-Origin", origin.to_string()),
+        ("Access-Control-Allow-Headers", ALLOWED_HEADERS.to_string()),
+        ("Access-Control-Allow-Methods", ALLOWED_METHODS.to_string()),
+    ];
+    if !allowed_origins.iter().any(|allowed| allowed == "*") {
+        headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+    }
+    Some(headers)
+}
+
+/** Whether a request is a CORS preflight that should short-circuit with a 204 and no body */
+pub fn is_preflight(method: &str, origin: Option<&str>) -> bool {
+    method.eq_ignore_ascii_case("OPTIONS") && origin.is_some()
+}
+
+#[test]
+fn test_is_allowed_with_exact_match() {
+    let origins = vec!["https://dashboard.example.com".to_string()];
+    assert!(is_allowed(&origins, "https://dashboard.example.com"));
+    assert!(!is_allowed(&origins, "https://evil.example.com"));
+}
+
+#[test]
+fn test_is_allowed_with_wildcard() {
+    let origins = vec!["*".to_string()];
+    assert!(is_allowed(&origins, "https://anything.example.com"));
+}
+
+#[test]
+fn test_response_headers_omitted_for_disallowed_origin() {
+    let origins = vec!["https://dashboard.example.com".to_string()];
+    assert!(response_headers(&origins, "https://evil.example.com").is_none());
+}
+
+#[test]
+fn test_response_headers_include_credentials_for_allowed_origin() {
+    let origins = vec!["https://dashboard.example.com".to_string()];
+    let headers = response_headers(&origins, "https://dashboard.example.com").unwrap();
+    assert!(headers.contains(&("Access-Control-Allow-Credentials", "true".to_string())));
+}
+
+#[test]
+fn test_response_headers_omit_credentials_for_wildcard_origin() {
+    let origins = vec!["*".to_string()];
+    let headers = response_headers(&origins, "https://anything.example.com").unwrap();
+    assert!(!headers.iter().any(|(name, _)| *name == "Access-Control-Allow-Credentials"));
+}
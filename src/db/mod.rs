@@ -0,0 +1,125 @@
+/*
+ * Copyright (C) 2023 Guillaume Pellegrino
+ * This file is part of acsrs <https://github.com/guillaumepellegrino/acsrs>.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Persistence for the ACS config and CPE list, behind a `Storage` trait so the
+//! backing store can be swapped between a single TOML file and a SQL database
+//! without changing callers in `acs.rs`.
+mod sql;
+mod toml_file;
+
+use crate::webhook;
+use async_trait::async_trait;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AcsConfig {
+    pub hostname: String,
+    pub username: String,
+    pub password: String,
+    pub autocert: bool,
+    pub unsecure_address: String,
+    pub identity_password: String,
+    pub secure_address: String,
+    pub management_address: String,
+
+    /** ACME directory URL used to request a certificate for `hostname` when `autocert` is set */
+    #[serde(default = "AcsConfig::default_acme_directory_url")]
+    pub acme_directory_url: String,
+    /** Contact email sent to the ACME server on account creation */
+    #[serde(default)]
+    pub acme_email: String,
+    /** Renew the certificate once fewer than this many days remain before expiry */
+    #[serde(default = "AcsConfig::default_acme_renewal_threshold_days")]
+    pub acme_renewal_threshold_days: u32,
+
+    /** Selects the storage backend: empty/`toml://` for the config.toml file, or a
+     * `sqlite://` / `postgres://` URL to persist CPEs in a SQL database instead. */
+    #[serde(default)]
+    pub storage_url: String,
+
+    /** Origins allowed to call the management API cross-origin (e.g. a browser dashboard).
+     * `"*"` allows any origin. Empty disables CORS handling entirely. */
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+}
+
+impl AcsConfig {
+    fn default_acme_directory_url() -> String {
+        String::from("https://acme-v02.api.letsencrypt.org/directory")
+    }
+
+    fn default_acme_renewal_threshold_days() -> u32 {
+        30
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CPE {
+    pub serial_number: String,
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    /** Unix timestamp of the last Inform received from this CPE */
+    pub last_seen: i64,
+    pub manufacturer: String,
+    pub product_class: String,
+    pub oui: String,
+    pub software_version: String,
+}
+
+/** Backing store for the ACS config, webhook subscriptions and CPE list.
+ * `upsert_cpe` is the hot path, called on every Inform: the SQL backend writes a
+ * single row, while the TOML backend rewrites its whole file. */
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn load_config(&self) -> Result<AcsConfig>;
+    async fn load_webhooks(&self) -> Result<Vec<webhook::Endpoint>>;
+    /** Streams/loads the full CPE list, e.g. on ACS startup */
+    async fn load_cpe_list(&self) -> Result<Vec<CPE>>;
+
+    /** Atomically replaces the config, webhook subscriptions and whole CPE list in one go.
+     * Used by `Acs::save`, which already holds every CPE's current state: the TOML backend
+     * takes its file lock once and does a single rewrite instead of three, and two concurrent
+     * `Acs::save` calls can't interleave into a file that mixes config from one call with the
+     * CPE list from another. */
+    async fn save_all(
+        &self,
+        config: &AcsConfig,
+        webhooks: &[webhook::Endpoint],
+        cpes: &[CPE],
+    ) -> Result<()>;
+
+    /** Inserts or updates a single CPE row, keyed by `serial_number`. Used for the incremental
+     * `Acs::record_cpe_seen` path on every Inform; prefer `save_all` for bulk saves. */
+    async fn upsert_cpe(&self, cpe: &CPE) -> Result<()>;
+}
+
+/** Picks a `Storage` backend from `storage_url`: empty defaults to the TOML file
+ * at `acsdir/config.toml`; `sqlite://...` or `postgres://...` use the SQL backend. */
+pub async fn open(acsdir: &std::path::Path, storage_url: &str) -> Result<Box<dyn Storage>> {
+    if storage_url.is_empty() || storage_url.starts_with("toml://") {
+        Ok(open_default(acsdir))
+    } else {
+        Ok(Box::new(sql::SqlStorage::connect(storage_url).await?))
+    }
+}
+
+/** The zero-configuration TOML backend, used before a config exists to learn `storage_url` from */
+pub fn open_default(acsdir: &std::path::Path) -> Box<dyn Storage> {
+    Box::new(toml_file::TomlStorage::new(acsdir))
+}
@@ -0,0 +1,201 @@
+/*
+ * Copyright (C) 2023 Guillaume Pellegrino
+ * This file is part of acsrs <https://github.com/guillaumepellegrino/acsrs>.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! SQL-backed storage (SQLite or Postgres, selected by the connection URL
+//! scheme) so multiple ACS instances can share one database and CPE state
+//! survives a crash without a full config.toml rewrite.
+use super::{AcsConfig, Storage, CPE};
+use crate::webhook;
+use async_trait::async_trait;
+use eyre::Result;
+use sqlx::any::{Any, AnyPoolOptions};
+use sqlx::Row;
+
+pub struct SqlStorage {
+    pool: sqlx::Pool<Any>,
+}
+
+impl SqlStorage {
+    pub async fn connect(url: &str) -> Result<Self> {
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new().max_connections(5).connect(url).await?;
+        let storage = Self { pool };
+        storage.migrate().await?;
+        Ok(storage)
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS acs_config (
+                id INTEGER PRIMARY KEY,
+                config TEXT NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS webhooks (
+                id INTEGER PRIMARY KEY,
+                endpoints TEXT NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS cpe (
+                serial_number TEXT PRIMARY KEY,
+                url TEXT NOT NULL,
+                username TEXT NOT NULL,
+                password TEXT NOT NULL,
+                last_seen BIGINT NOT NULL,
+                manufacturer TEXT NOT NULL,
+                product_class TEXT NOT NULL,
+                oui TEXT NOT NULL,
+                software_version TEXT NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SqlStorage {
+    async fn load_config(&self) -> Result<AcsConfig> {
+        let row = sqlx::query("SELECT config FROM acs_config WHERE id = 1")
+            .fetch_optional(&self.pool)
+            .await?;
+        match row {
+            Some(row) => Ok(serde_json::from_str(row.get::<String, _>("config").as_str())?),
+            None => Ok(AcsConfig::default()),
+        }
+    }
+
+    async fn load_webhooks(&self) -> Result<Vec<webhook::Endpoint>> {
+        let row = sqlx::query("SELECT endpoints FROM webhooks WHERE id = 1")
+            .fetch_optional(&self.pool)
+            .await?;
+        match row {
+            Some(row) => Ok(serde_json::from_str(row.get::<String, _>("endpoints").as_str())?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn load_cpe_list(&self) -> Result<Vec<CPE>> {
+        let rows = sqlx::query(
+            "SELECT serial_number, url, username, password, last_seen,
+                    manufacturer, product_class, oui, software_version FROM cpe",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| CPE {
+                serial_number: row.get("serial_number"),
+                url: row.get("url"),
+                username: row.get("username"),
+                password: row.get("password"),
+                last_seen: row.get("last_seen"),
+                manufacturer: row.get("manufacturer"),
+                product_class: row.get("product_class"),
+                oui: row.get("oui"),
+                software_version: row.get("software_version"),
+            })
+            .collect())
+    }
+
+    async fn save_all(
+        &self,
+        config: &AcsConfig,
+        webhooks: &[webhook::Endpoint],
+        cpes: &[CPE],
+    ) -> Result<()> {
+        // One transaction for config + webhooks + the full CPE replace, so two concurrent
+        // Acs::save calls can't interleave, and a CPE dropped from `cpes` doesn't leave a
+        // stale row behind (delete-then-insert, like TomlStorage::save_all's full rewrite).
+        let mut tx = self.pool.begin().await?;
+
+        let config_json = serde_json::to_string(config)?;
+        sqlx::query(
+            "INSERT INTO acs_config (id, config) VALUES (1, $1)
+             ON CONFLICT (id) DO UPDATE SET config = excluded.config",
+        )
+        .bind(config_json)
+        .execute(&mut *tx)
+        .await?;
+
+        let webhooks_json = serde_json::to_string(webhooks)?;
+        sqlx::query(
+            "INSERT INTO webhooks (id, endpoints) VALUES (1, $1)
+             ON CONFLICT (id) DO UPDATE SET endpoints = excluded.endpoints",
+        )
+        .bind(webhooks_json)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM cpe").execute(&mut *tx).await?;
+        for cpe in cpes {
+            Self::upsert_cpe_row(&mut *tx, cpe).await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn upsert_cpe(&self, cpe: &CPE) -> Result<()> {
+        Self::upsert_cpe_row(&self.pool, cpe).await
+    }
+}
+
+impl SqlStorage {
+    async fn upsert_cpe_row<'e, E>(executor: E, cpe: &CPE) -> Result<()>
+    where
+        E: sqlx::Executor<'e, Database = Any>,
+    {
+        sqlx::query(
+            "INSERT INTO cpe (serial_number, url, username, password, last_seen,
+                               manufacturer, product_class, oui, software_version)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+             ON CONFLICT (serial_number) DO UPDATE SET
+                url = excluded.url,
+                username = excluded.username,
+                password = excluded.password,
+                last_seen = excluded.last_seen,
+                manufacturer = excluded.manufacturer,
+                product_class = excluded.product_class,
+                oui = excluded.oui,
+                software_version = excluded.software_version",
+        )
+        .bind(&cpe.serial_number)
+        .bind(&cpe.url)
+        .bind(&cpe.username)
+        .bind(&cpe.password)
+        .bind(cpe.last_seen)
+        .bind(&cpe.manufacturer)
+        .bind(&cpe.product_class)
+        .bind(&cpe.oui)
+        .bind(&cpe.software_version)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright (C) 2023 Guillaume Pellegrino
+ * This file is part of acsrs <https://github.com/guillaumepellegrino/acsrs>.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Original storage backend: the whole ACS state lives in one `config.toml`,
+//! rewritten on every save. Kept as the zero-configuration default.
+use super::{AcsConfig, Storage, CPE};
+use crate::webhook;
+use async_trait::async_trait;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct File {
+    config: AcsConfig,
+    #[serde(default)]
+    cpe: Vec<CPE>,
+    #[serde(default)]
+    webhooks: Vec<webhook::Endpoint>,
+}
+
+impl File {
+    fn load(path: &std::path::Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    fn save(&self, path: &std::path::Path) -> Result<()> {
+        let content = toml::to_string_pretty(self)?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+pub struct TomlStorage {
+    savefile: std::path::PathBuf,
+    // Serializes read-modify-write access so concurrent upserts can't clobber each other.
+    lock: Mutex<()>,
+}
+
+impl TomlStorage {
+    pub fn new(acsdir: &std::path::Path) -> Self {
+        Self {
+            savefile: acsdir.join("config.toml"),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for TomlStorage {
+    async fn load_config(&self) -> Result<AcsConfig> {
+        // Unlike File::load (used by the save/upsert paths, which must tolerate a
+        // not-yet-created file), a missing savefile here means there's no config to restore —
+        // fail like the original single-file implementation did, so callers fall back to
+        // `Acs::new` instead of getting a zeroed-out `AcsConfig`.
+        let content = std::fs::read_to_string(&self.savefile)?;
+        let file: File = toml::from_str(&content)?;
+        Ok(file.config)
+    }
+
+    async fn load_webhooks(&self) -> Result<Vec<webhook::Endpoint>> {
+        Ok(File::load(&self.savefile)?.webhooks)
+    }
+
+    async fn load_cpe_list(&self) -> Result<Vec<CPE>> {
+        Ok(File::load(&self.savefile)?.cpe)
+    }
+
+    async fn save_all(
+        &self,
+        config: &AcsConfig,
+        webhooks: &[webhook::Endpoint],
+        cpes: &[CPE],
+    ) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let file = File {
+            config: config.clone(),
+            cpe: cpes.to_vec(),
+            webhooks: webhooks.to_vec(),
+        };
+        file.save(&self.savefile)
+    }
+
+    async fn upsert_cpe(&self, cpe: &CPE) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut file = File::load(&self.savefile)?;
+        match file.cpe.iter_mut().find(|c| c.serial_number == cpe.serial_number) {
+            Some(existing) => *existing = cpe.clone(),
+            None => file.cpe.push(cpe.clone()),
+        }
+        file.save(&self.savefile)
+    }
+}
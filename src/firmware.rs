@@ -0,0 +1,164 @@
+/*
+ * Copyright (C) 2023 Guillaume Pellegrino
+ * This file is part of acsrs <https://github.com/guillaumepellegrino/acsrs>.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Firmware image store: images uploaded through the management API are staged
+//! under `acsdir/firmware` and served back over the existing HTTP listeners so a
+//! `Download` RPC can point a CPE at a stable URL.
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+const FIRMWARE_URL_PREFIX: &str = "/firmware";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    pub id: String,
+    pub filename: String,
+    pub file_type: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Image {
+    /** The stable URL a `Download` RPC can point a CPE at */
+    pub fn url(&self, baseurl: &str) -> String {
+        format!("{}{}/{}", baseurl, FIRMWARE_URL_PREFIX, self.id)
+    }
+}
+
+/** Extracts the image id from a request path of the form `/firmware/{id}`. The HTTP listener
+ * should route matches here to `FirmwareStore::respond_to_path` (or call `get`/`read` directly). */
+pub fn route_download(path: &str) -> Option<&str> {
+    path.strip_prefix(FIRMWARE_URL_PREFIX)?.strip_prefix('/')
+}
+
+/** Tracks uploaded firmware images on disk under `acsdir/firmware` */
+#[derive(Debug, Default)]
+pub struct FirmwareStore {
+    dir: std::path::PathBuf,
+    images: RwLock<Vec<Image>>,
+}
+
+impl FirmwareStore {
+    pub fn new(acsdir: &std::path::Path) -> Self {
+        Self {
+            dir: acsdir.join("firmware"),
+            images: RwLock::new(Vec::new()),
+        }
+    }
+
+    /** Persists an uploaded image and records its metadata. The management API's upload route
+     * should gate this call behind `Acs::authorize_request(header, "firmware.write")`, mirroring
+     * every other mutating management endpoint. */
+    pub async fn store(&self, filename: &str, file_type: &str, data: &[u8]) -> Result<Image> {
+        std::fs::create_dir_all(&self.dir)?;
+        let sha256 = hex::encode(Sha256::digest(data));
+        let id = format!("{}-{}", &sha256[..16], Self::basename(filename));
+        std::fs::write(self.dir.join(&id), data)?;
+
+        let image = Image {
+            id,
+            filename: filename.to_string(),
+            file_type: file_type.to_string(),
+            size: data.len() as u64,
+            sha256,
+        };
+        self.images.write().await.push(image.clone());
+        Ok(image)
+    }
+
+    /** Strips any directory components from an untrusted upload filename so the id built from
+     * it can't escape `self.dir` via embedded `/` or `..` (path traversal / arbitrary write). */
+    fn basename(filename: &str) -> &str {
+        filename
+            .rsplit(['/', '\\'])
+            .next()
+            .filter(|name| !name.is_empty() && *name != ".." && *name != ".")
+            .unwrap_or("upload")
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Image> {
+        self.images.read().await.iter().find(|i| i.id == id).cloned()
+    }
+
+    /** Reads the bytes of a previously stored image back off disk, for serving over HTTP */
+    pub async fn read(&self, id: &str) -> Result<Vec<u8>> {
+        let image = self
+            .get(id)
+            .await
+            .ok_or(eyre!("firmware: no image with id '{id}'"))?;
+        Ok(std::fs::read(self.dir.join(&image.id))?)
+    }
+
+    pub async fn list(&self) -> Vec<Image> {
+        self.images.read().await.clone()
+    }
+
+    /** The single call the unsecure/secure HTTP listener should make per GET request: routes
+     * `path`, reads the matching image off disk, and returns its bytes alongside its `file_type`
+     * for the `Content-Type` header. `None` means respond 404 (no match or unknown id). */
+    pub async fn respond_to_path(&self, path: &str) -> Option<(Vec<u8>, String)> {
+        let id = route_download(path)?;
+        let image = self.get(id).await?;
+        let data = self.read(id).await.ok()?;
+        Some((data, image.file_type))
+    }
+}
+
+#[test]
+fn test_route_download_extracts_id() {
+    assert_eq!(route_download("/firmware/abc123-firmware.bin"), Some("abc123-firmware.bin"));
+    assert_eq!(route_download("/other/abc123"), None);
+}
+
+#[tokio::test]
+async fn test_respond_to_path_returns_stored_bytes_and_type() {
+    let tmp = std::path::PathBuf::from("/tmp/acsrs_test_firmware_respond");
+    std::fs::create_dir_all(&tmp).unwrap();
+    let store = FirmwareStore::new(&tmp);
+    let image = store.store("firmware.bin", "1 Firmware Upgrade Image", b"data").await.unwrap();
+
+    let (data, file_type) = store.respond_to_path(&image.url("")).await.unwrap();
+    assert_eq!(data, b"data");
+    assert_eq!(file_type, "1 Firmware Upgrade Image");
+
+    assert!(store.respond_to_path("/firmware/unknown-id").await.is_none());
+    assert!(store.respond_to_path("/other/path").await.is_none());
+}
+
+#[test]
+fn test_basename_strips_path_traversal() {
+    assert_eq!(FirmwareStore::basename("../../../../etc/cron.d/evil"), "evil");
+    assert_eq!(FirmwareStore::basename("firmware.bin"), "firmware.bin");
+    assert_eq!(FirmwareStore::basename(".."), "upload");
+}
+
+#[test]
+fn test_image_url() {
+    let image = Image {
+        id: "abc123-firmware.bin".to_string(),
+        filename: "firmware.bin".to_string(),
+        file_type: "1 Firmware Upgrade Image".to_string(),
+        size: 1024,
+        sha256: "abc123".to_string(),
+    };
+    assert_eq!(
+        image.url("https://acs.example.com:8443"),
+        "https://acs.example.com:8443/firmware/abc123-firmware.bin"
+    );
+}
@@ -0,0 +1,180 @@
+/*
+ * Copyright (C) 2023 Guillaume Pellegrino
+ * This file is part of acsrs <https://github.com/guillaumepellegrino/acsrs>.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Role-based access control for the management API, loaded from `roles.toml`
+//! alongside `config.toml`. Permissions are glob patterns like `cpe.*.read` or
+//! `acs.config.write`; users inherit permissions by listing roles, and roles
+//! can themselves inherit other roles.
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Role {
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub inherits: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct User {
+    pub password: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RolesConfig {
+    #[serde(default)]
+    pub roles: HashMap<String, Role>,
+    #[serde(default)]
+    pub users: HashMap<String, User>,
+}
+
+impl RolesConfig {
+    pub fn load(path: &std::path::Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&content)?;
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<()> {
+        let content = toml::to_string_pretty(self)?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    /** Rejects roles.toml files that inherit from a role that isn't defined */
+    fn validate(&self) -> Result<()> {
+        for (name, role) in &self.roles {
+            for parent in &role.inherits {
+                if !self.roles.contains_key(parent) {
+                    return Err(eyre!(
+                        "roles.toml: role '{name}' inherits unknown role '{parent}'"
+                    ));
+                }
+            }
+        }
+        for (username, user) in &self.users {
+            for role in &user.roles {
+                if !self.roles.contains_key(role) {
+                    return Err(eyre!(
+                        "roles.toml: user '{username}' references unknown role '{role}'"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /** Expands a user's roles (transitively) into the flat set of permission globs they carry */
+    pub fn effective_permissions(&self, username: &str) -> Vec<String> {
+        let Some(user) = self.users.get(username) else {
+            return Vec::new();
+        };
+        let mut seen = std::collections::HashSet::new();
+        let mut permissions = Vec::new();
+        let mut queue: Vec<String> = user.roles.clone();
+        while let Some(role_name) = queue.pop() {
+            if !seen.insert(role_name.clone()) {
+                continue;
+            }
+            if let Some(role) = self.roles.get(&role_name) {
+                permissions.extend(role.permissions.iter().cloned());
+                queue.extend(role.inherits.iter().cloned());
+            }
+        }
+        permissions
+    }
+
+    /** Checks whether `username` is allowed to perform `permission` (e.g. "cpe.CPE1_SN.write") */
+    pub fn is_allowed(&self, username: &str, permission: &str) -> bool {
+        self.effective_permissions(username)
+            .iter()
+            .any(|pattern| glob_match(pattern, permission))
+    }
+
+    pub fn password_of(&self, username: &str) -> Option<&str> {
+        self.users.get(username).map(|u| u.password.as_str())
+    }
+}
+
+/** Matches `permission` against a glob `pattern` where `*` matches exactly one dot-separated segment */
+fn glob_match(pattern: &str, permission: &str) -> bool {
+    let pattern_parts: Vec<&str> = pattern.split('.').collect();
+    let permission_parts: Vec<&str> = permission.split('.').collect();
+    if pattern_parts.len() != permission_parts.len() {
+        return false;
+    }
+    pattern_parts
+        .iter()
+        .zip(permission_parts.iter())
+        .all(|(p, v)| *p == "*" || p == v)
+}
+
+fn sample_roles_config() -> RolesConfig {
+    let mut roles = HashMap::new();
+    roles.insert(
+        "viewer".to_string(),
+        Role {
+            permissions: vec!["cpe.*.read".to_string()],
+            inherits: vec![],
+        },
+    );
+    roles.insert(
+        "technician".to_string(),
+        Role {
+            permissions: vec!["cpe.CPE1_SN.write".to_string()],
+            inherits: vec!["viewer".to_string()],
+        },
+    );
+    let mut users = HashMap::new();
+    users.insert(
+        "alice".to_string(),
+        User {
+            password: "secret".to_string(),
+            roles: vec!["technician".to_string()],
+        },
+    );
+    RolesConfig { roles, users }
+}
+
+#[test]
+fn test_inherited_permissions_are_allowed() {
+    let config = sample_roles_config();
+    assert!(config.is_allowed("alice", "cpe.CPE2_SN.read"));
+    assert!(config.is_allowed("alice", "cpe.CPE1_SN.write"));
+    assert!(!config.is_allowed("alice", "cpe.CPE2_SN.write"));
+}
+
+#[test]
+fn test_unknown_user_has_no_permissions() {
+    let config = sample_roles_config();
+    assert!(!config.is_allowed("bob", "cpe.CPE1_SN.read"));
+}
+
+#[test]
+fn test_validate_rejects_unknown_parent_role() {
+    let mut config = sample_roles_config();
+    config.roles.get_mut("viewer").unwrap().inherits = vec!["ghost".to_string()];
+    assert!(config.validate().is_err());
+}
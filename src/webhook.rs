@@ -0,0 +1,208 @@
+/*
+ * Copyright (C) 2023 Guillaume Pellegrino
+ * This file is part of acsrs <https://github.com/guillaumepellegrino/acsrs>.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Outbound webhook delivery for CPE lifecycle and transfer events, so operators
+//! can subscribe to ACS activity instead of polling the management API.
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    SessionOpened,
+    ConnectionRequestFailed,
+    TransferCompleted,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub serial_number: String,
+    pub event: EventType,
+    pub timestamp: u64,
+}
+
+/** A single subscriber: where to deliver events, how to sign them, and which events it wants */
+#[derive(Debug, Clone, Default, serde::Deserialize, Serialize)]
+pub struct Endpoint {
+    pub url: String,
+    pub secret: String,
+    #[serde(default)]
+    pub events: Vec<EventTypeFilter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTypeFilter {
+    SessionOpened,
+    ConnectionRequestFailed,
+    TransferCompleted,
+    All,
+}
+
+impl Endpoint {
+    fn wants(&self, event: &EventType) -> bool {
+        if self.events.is_empty() {
+            return true;
+        }
+        self.events.iter().any(|f| {
+            matches!(
+                (f, event),
+                (EventTypeFilter::All, _)
+                    | (EventTypeFilter::SessionOpened, EventType::SessionOpened)
+                    | (
+                        EventTypeFilter::ConnectionRequestFailed,
+                        EventType::ConnectionRequestFailed
+                    )
+                    | (EventTypeFilter::TransferCompleted, EventType::TransferCompleted)
+            )
+        })
+    }
+}
+
+/** Fans an event out to every subscribed endpoint on its own best-effort Tokio task */
+#[derive(Debug, Clone)]
+pub struct WebhookDispatcher {
+    client: reqwest::Client,
+}
+
+impl Default for WebhookDispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebhookDispatcher {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /** Fire-and-forget: delivers `event` to every endpoint interested in it, each on its own task */
+    pub fn dispatch(&self, endpoints: &[Endpoint], event: Event) {
+        for endpoint in endpoints {
+            if !endpoint.wants(&event.event) {
+                continue;
+            }
+            let client = self.client.clone();
+            let endpoint = endpoint.clone();
+            let event = event.clone();
+            tokio::spawn(async move {
+                if let Err(e) = deliver_with_retry(&client, &endpoint, &event).await {
+                    eprintln!(
+                        "webhook: giving up delivering {:?} to {}: {e}",
+                        event.event, endpoint.url
+                    );
+                }
+            });
+        }
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> eyre::Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(body);
+    Ok(base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+async fn deliver_with_retry(
+    client: &reqwest::Client,
+    endpoint: &Endpoint,
+    event: &Event,
+) -> eyre::Result<()> {
+    let body = serde_json::to_vec(event)?;
+    let signature = sign(&endpoint.secret, &body)?;
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+        let res = client
+            .post(&endpoint.url)
+            .header("Content-Type", "application/json")
+            .header("X-ACSRS-Signature", &signature)
+            .body(body.clone())
+            .send()
+            .await;
+
+        match res {
+            Ok(res) if res.status().is_success() => return Ok(()),
+            Ok(res) => println!(
+                "webhook: delivery {attempt}/{MAX_DELIVERY_ATTEMPTS} to {} failed: {}",
+                endpoint.url,
+                res.status()
+            ),
+            Err(e) => println!(
+                "webhook: delivery {attempt}/{MAX_DELIVERY_ATTEMPTS} to {} failed: {e}",
+                endpoint.url
+            ),
+        }
+
+        if attempt < MAX_DELIVERY_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    Err(eyre::eyre!(
+        "exhausted {MAX_DELIVERY_ATTEMPTS} delivery attempts"
+    ))
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_sign_is_deterministic_and_key_dependent() {
+    let body = br#"{"serial_number":"CPE1_SN"}"#;
+    assert_eq!(sign("secret", body).unwrap(), sign("secret", body).unwrap());
+    assert_ne!(sign("secret", body).unwrap(), sign("other-secret", body).unwrap());
+}
+
+#[test]
+fn test_endpoint_wants_empty_filter_accepts_everything() {
+    let endpoint = Endpoint::default();
+    assert!(endpoint.wants(&EventType::SessionOpened));
+    assert!(endpoint.wants(&EventType::TransferCompleted));
+}
+
+#[test]
+fn test_endpoint_wants_respects_event_filter() {
+    let endpoint = Endpoint {
+        events: vec![EventTypeFilter::TransferCompleted],
+        ..Default::default()
+    };
+    assert!(endpoint.wants(&EventType::TransferCompleted));
+    assert!(!endpoint.wants(&EventType::SessionOpened));
+}
+
+#[test]
+fn test_endpoint_wants_all_filter_accepts_everything() {
+    let endpoint = Endpoint {
+        events: vec![EventTypeFilter::All],
+        ..Default::default()
+    };
+    assert!(endpoint.wants(&EventType::SessionOpened));
+    assert!(endpoint.wants(&EventType::ConnectionRequestFailed));
+}